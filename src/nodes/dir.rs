@@ -13,19 +13,147 @@
 // under the License.
 
 extern crate fuse;
+extern crate lazy_static;
+extern crate sha1;
+extern crate threadpool;
 extern crate time;
 
 use {Cache, IdGenerator};
 use failure::{Error, ResultExt};
 use nix::{errno, unistd};
 use nodes::{KernelError, Node, NodeResult, conv};
-use std::collections::HashMap;
+use nodes::file::{BufferFile, File, VirtualFile};
+use self::lazy_static::lazy_static;
+use self::sha1::Sha1;
+use self::threadpool::ThreadPool;
+use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirEntryExt, MetadataExt};
 use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 
+/// Maximum number of threads used to stat directory entries in parallel during `readdir`.
+///
+/// This mirrors the bound used by similar filesystem-status code elsewhere: large enough to hide
+/// the latency of slow (e.g. networked) backends, small enough to never exhaust file descriptors
+/// or overwhelm the scheduler on huge directories.
+const READDIR_STAT_CONCURRENCY: usize = 16;
+
+lazy_static! {
+    /// Process-wide worker pool used to stat directory entries in parallel during `readdir`.
+    ///
+    /// This is shared across every `readdir` call instead of being spun up fresh each time:
+    /// `readdir` on a hot, frequently-listed directory can run often enough that paying
+    /// `READDIR_STAT_CONCURRENCY` thread creations per call would dwarf the cost of the stats
+    /// themselves.
+    static ref STAT_POOL: ThreadPool = ThreadPool::new(READDIR_STAT_CONCURRENCY);
+}
+
+/// Returns true if a stat observed at `now` with the given `mtime` can't be trusted to be stable.
+///
+/// A `mtime` that falls in the same wall-clock second as `now` is ambiguous on filesystems with
+/// only 1-second mtime granularity: the underlying file could change again later within that same
+/// second without the mtime ever taking on a different value, so such a stat must never be cached
+/// or treated as proof that nothing has changed since.
+pub(crate) fn is_second_ambiguous(mtime: time::Timespec, now: time::Timespec) -> bool {
+    mtime.sec == now.sec
+}
+
+/// Stats every entry in `entries`, fanning the calls out across a small bounded worker pool.
+///
+/// Below `READDIR_STAT_CONCURRENCY` entries, this stays single-threaded to avoid the overhead of
+/// dispatching to the pool for what is already a cheap scan.  Each entry is paired with its own
+/// stat result; pairs are returned in the same order as `entries`.
+fn stat_entries(entries: Vec<fs::DirEntry>) -> Vec<(fs::DirEntry, io::Result<fs::Metadata>)> {
+    if entries.len() < READDIR_STAT_CONCURRENCY {
+        return entries.into_iter().map(|entry| {
+            let metadata = entry.metadata();
+            (entry, metadata)
+        }).collect();
+    }
+
+    let count = entries.len();
+    let (tx, rx) = mpsc::channel();
+    for (i, entry) in entries.into_iter().enumerate() {
+        let tx = tx.clone();
+        STAT_POOL.execute(move || {
+            let metadata = entry.metadata();
+            tx.send((i, entry, metadata)).expect("Receiver outlives every queued job");
+        });
+    }
+    drop(tx);
+
+    let mut results: Vec<Option<(fs::DirEntry, io::Result<fs::Metadata>)>> =
+        Vec::with_capacity(count);
+    results.resize_with(count, || None);
+    for (i, entry, metadata) in rx.iter().take(count) {
+        results[i] = Some((entry, metadata));
+    }
+    results.into_iter()
+        .map(|result| result.expect("every entry index is claimed exactly once"))
+        .collect()
+}
+
+/// Record of the last full `readdir` scan of a mapped directory, used to detect whether the
+/// underlying directory has changed since.
+struct DirScan {
+    /// mtime of the directory itself as observed at scan time.
+    mtime: time::Timespec,
+    /// Digest over the sorted (name, type, inode) tuples of the directory's on-disk children, as
+    /// observed at scan time.
+    digest: [u8; 20],
+}
+
+/// Computes a cheap digest over a directory's children, used to detect whether its contents have
+/// changed without re-stating every entry.
+///
+/// `entries` need not be sorted; this function sorts a local copy by name so that the digest is
+/// independent of the order in which the underlying file system happens to return entries.
+fn compute_signature(entries: &[(OsString, fuse::FileType, u64)]) -> [u8; 20] {
+    let mut sorted: Vec<&(OsString, fuse::FileType, u64)> = entries.iter().collect();
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut hasher = Sha1::new();
+    for (name, file_type, inode) in sorted {
+        hasher.update(name.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(format!("{:?}", file_type).as_bytes());
+        hasher.update(&[0]);
+        hasher.update(&inode.to_le_bytes());
+        hasher.update(&[0]);
+    }
+    hasher.digest().bytes()
+}
+
+/// Returns true if `scan` proves that the directory's on-disk children are unchanged based solely
+/// on its mtime, letting `readdir` skip `read_dir` and every per-entry stat entirely.
+///
+/// This only ever holds when the observed mtime is not second-ambiguous (see
+/// `is_second_ambiguous`): an ambiguous mtime could hide a change that happened within the same
+/// wall-clock second, so it must always force a full scan regardless of whether it matches `scan`.
+fn mtime_fast_path_applies(scan: Option<&DirScan>, dir_mtime: time::Timespec, ambiguous: bool) -> bool {
+    !ambiguous && scan.map_or(false, |scan| scan.mtime == dir_mtime)
+}
+
+/// Returns true if `scan`'s digest matches `digest`, letting `readdir` skip the full per-entry stat
+/// pass because the set of on-disk children is unchanged even though the directory's mtime moved
+/// (e.g. a no-op rewrite).
+fn digest_fast_path_applies(scan: Option<&DirScan>, digest: &[u8; 20]) -> bool {
+    scan.map_or(false, |scan| &scan.digest == digest)
+}
+
+/// Drops non-explicit children that no longer exist on disk, so that a subsequent `lookup` of a
+/// deleted file reports ENOENT instead of resurrecting a stale node.  Explicit mappings are never
+/// evicted, since they don't come from -- and so can't be confirmed or refuted by -- the on-disk
+/// scan that produced `seen`.
+fn evict_stale_children(children: &mut HashMap<OsString, Dirent>, seen: &HashSet<OsString>) {
+    children.retain(|name, dirent| dirent.explicit_mapping || seen.contains(name));
+}
+
 /// Takes the components of a path and returns the first normal component and the rest.
 ///
 /// This assumes that the input path is normalized and that the very first component is a normal
@@ -49,6 +177,10 @@ struct Dirent {
 pub struct Dir {
     inode: u64,
     writable: bool,
+    /// How long a cached `attr` may be served without re-stating the underlying directory.  A
+    /// `None` value disables attribute caching entirely, which is useful for callers that need
+    /// fully reproducible (if slower) behavior.
+    attr_ttl: Option<time::Duration>,
     state: Mutex<MutableDir>,
 }
 
@@ -58,6 +190,15 @@ struct MutableDir {
     underlying_path: Option<PathBuf>,
     attr: fuse::FileAttr,
     children: HashMap<OsString, Dirent>,
+    /// Wall-clock time at which `attr` was last refreshed from disk, and whether that refresh
+    /// landed in the same wall-clock second as the observed mtime.  Such "second-ambiguous"
+    /// entries can't be trusted: a 1-second-granularity mtime could change again within that same
+    /// second without us ever seeing a different value, so they must always be re-stated.  `None`
+    /// until the first stat.
+    cached_at: Option<(time::Timespec, bool)>,
+    /// Record of the last full `readdir` scan, used to skip re-scanning an unchanged directory.
+    /// `None` until the first `readdir`.
+    scan: Option<DirScan>,
 }
 
 impl Dir {
@@ -87,11 +228,14 @@ impl Dir {
             underlying_path: None,
             attr: attr,
             children: HashMap::new(),
+            cached_at: None,
+            scan: None,
         };
 
         Arc::new(Dir {
             inode: inode,
             writable: false,
+            attr_ttl: None,
             state: Mutex::from(state),
         })
     }
@@ -105,8 +249,11 @@ impl Dir {
     /// `fs_attr` is an input parameter because, by the time we decide to instantiate a directory
     /// node (e.g. as we discover directory entries during readdir or lookup), we have already
     /// issued a stat on the underlying file system and we cannot re-do it for efficiency reasons.
-    pub fn new_mapped(inode: u64, underlying_path: &Path, fs_attr: &fs::Metadata, writable: bool)
-        -> Arc<Node> {
+    ///
+    /// `attr_ttl` is the mount-time knob controlling how long `getattr` may serve a cached
+    /// attribute before re-stating the underlying directory; `None` disables caching.
+    pub fn new_mapped(inode: u64, underlying_path: &Path, fs_attr: &fs::Metadata, writable: bool,
+        attr_ttl: Option<time::Duration>) -> Arc<Node> {
         if !fs_attr.is_dir() {
             panic!("Can only construct based on dirs");
         }
@@ -117,9 +264,11 @@ impl Dir {
             underlying_path: Some(PathBuf::from(underlying_path)),
             attr: attr,
             children: HashMap::new(),
+            cached_at: None,
+            scan: None,
         };
 
-        Arc::new(Dir { inode, writable, state: Mutex::from(state) })
+        Arc::new(Dir { inode, writable, attr_ttl, state: Mutex::from(state) })
     }
 
     /// Creates a new scaffold directory as a child of the current one.
@@ -137,7 +286,8 @@ impl Dir {
             match fs::symlink_metadata(&child_path) {
                 Ok(fs_attr) => {
                     if fs_attr.is_dir() {
-                        return Dir::new_mapped(ids.next(), &child_path, &fs_attr, self.writable);
+                        return Dir::new_mapped(
+                            ids.next(), &child_path, &fs_attr, self.writable, self.attr_ttl);
                     }
 
                     info!("Mapping clobbers non-directory {} with an immutable directory",
@@ -152,6 +302,27 @@ impl Dir {
         }
         Dir::new_empty(ids.next(), Some(self), now)
     }
+
+    /// Creates a new child backed by an in-memory byte buffer and inserts it into this directory
+    /// as an explicit mapping.
+    ///
+    /// This is the `Dir`-side counterpart to `File::new_virtual`: it lets a mapping splat synthetic
+    /// content -- e.g. a small generated config or a redirect stub -- into the sandbox without it
+    /// ever having existed on disk.  Unlike `map`, this only maps a single, already-resolved name
+    /// directly below this directory; it does not walk or create intermediate scaffold components.
+    pub fn map_buffer(&self, name: &OsStr, content: Vec<u8>, writable: bool, ids: &IdGenerator,
+        now: time::Timespec) -> Arc<Node> {
+        let inode = ids.next();
+        let buffer = BufferFile::new(content, writable, now);
+        let attr = buffer.getattr(inode).expect("BufferFile::getattr never fails");
+        let child = File::new_virtual(inode, Arc::new(buffer), attr, writable);
+
+        let mut state = self.state.lock().unwrap();
+        let dirent = Dirent { node: child.clone(), explicit_mapping: true };
+        state.children.insert(name.to_os_string(), dirent);
+
+        child
+    }
 }
 
 impl Node for Dir {
@@ -202,6 +373,14 @@ impl Node for Dir {
     fn getattr(&self) -> NodeResult<fuse::FileAttr> {
         let mut state = self.state.lock().unwrap();
 
+        if let Some(ttl) = self.attr_ttl {
+            if let Some((cached_at, ambiguous)) = state.cached_at {
+                if !ambiguous && time::get_time() - cached_at < ttl {
+                    return Ok(state.attr);
+                }
+            }
+        }
+
         let new_attr = match state.underlying_path.as_ref() {
             Some(path) => {
                 let fs_attr = fs::symlink_metadata(path)?;
@@ -210,7 +389,10 @@ impl Node for Dir {
                         path, fs_attr.file_type());
                     return Err(KernelError::from_errno(errno::Errno::EIO));
                 }
-                Some(conv::attr_fs_to_fuse(path, self.inode, &fs_attr))
+                let now = time::get_time();
+                let attr = conv::attr_fs_to_fuse(path, self.inode, &fs_attr);
+                state.cached_at = Some((now, is_second_ambiguous(attr.mtime, now)));
+                Some(attr)
             },
             None => None,
         };
@@ -250,31 +432,102 @@ impl Node for Dir {
 
     fn readdir(&self, ids: &IdGenerator, cache: &Cache, reply: &mut fuse::ReplyDirectory)
         -> NodeResult<()> {
-        let mut state = self.state.lock().unwrap();
-
         reply.add(self.inode, 0, fuse::FileType::Directory, ".");
-        reply.add(state.parent, 1, fuse::FileType::Directory, "..");
         let mut pos = 2;
 
-        // First, return the entries that correspond to explicit mappings performed by the user at
-        // either mount time or during a reconfiguration.  Those should clobber any on-disk
-        // contents that we discover later when we issue the readdir on the underlying directory,
-        // if any.
-        for (name, dirent) in &state.children {
-            if dirent.explicit_mapping {
-                reply.add(dirent.node.inode(), pos, dirent.node.file_type_cached(), name);
-                pos += 1;
+        // Grab just what we need from the node while holding the lock, then release it: the
+        // per-entry stat calls below can be slow on high-latency backends and must not block
+        // concurrent lookups/getattrs on this same directory.
+        let underlying_path = {
+            let state = self.state.lock().unwrap();
+            reply.add(state.parent, 1, fuse::FileType::Directory, "..");
+
+            // First, return the entries that correspond to explicit mappings performed by the user
+            // at either mount time or during a reconfiguration.  Those should clobber any on-disk
+            // contents that we discover later when we issue the readdir on the underlying
+            // directory, if any.
+            for (name, dirent) in &state.children {
+                if dirent.explicit_mapping {
+                    reply.add(dirent.node.inode(), pos, dirent.node.file_type_cached(), name);
+                    pos += 1;
+                }
+            }
+
+            match state.underlying_path.as_ref() {
+                Some(path) => path.clone(),
+                None => return Ok(()),
+            }
+        };
+
+        // Stat the directory itself first, before touching the underlying directory's contents at
+        // all: if its mtime hasn't moved since the last full scan, and that mtime isn't
+        // second-ambiguous, we already know the full set of children from that scan and can skip
+        // `read_dir` -- and every per-entry stat -- entirely.
+        let dir_attr = fs::symlink_metadata(&underlying_path)?;
+        let dir_mtime = time::Timespec::new(dir_attr.mtime(), dir_attr.mtime_nsec() as i32);
+        let now = time::get_time();
+        let ambiguous = is_second_ambiguous(dir_mtime, now);
+
+        {
+            let state = self.state.lock().unwrap();
+            let mtime_unchanged = mtime_fast_path_applies(state.scan.as_ref(), dir_mtime, ambiguous);
+            if mtime_unchanged {
+                for (name, dirent) in &state.children {
+                    if !dirent.explicit_mapping {
+                        reply.add(dirent.node.inode(), pos, dirent.node.file_type_cached(), name);
+                        pos += 1;
+                    }
+                }
+                return Ok(());
             }
         }
 
-        if state.underlying_path.as_ref().is_none() {
-            return Ok(());
+        // The directory's mtime moved (or is ambiguous): we have to actually list it.  Reading
+        // each entry's type and inode off the `dirent` itself (no extra stat syscall) is cheap
+        // enough to always do, and lets us compute a signature to detect that the set of entries
+        // is unchanged before paying for the full per-entry stats.
+        let entries: Vec<fs::DirEntry> = fs::read_dir(&underlying_path)?.collect::<io::Result<_>>()?;
+        let mut cheap_entries: Vec<(OsString, fuse::FileType, u64)> = Vec::with_capacity(entries.len());
+        for entry in &entries {
+            let fs_type = conv::filetype_fs_to_fuse(&entry.path(), entry.file_type()?);
+            cheap_entries.push((entry.file_name(), fs_type, entry.ino()));
         }
+        let digest = compute_signature(&cheap_entries);
+
+        {
+            let mut state = self.state.lock().unwrap();
+            let contents_unchanged = digest_fast_path_applies(state.scan.as_ref(), &digest);
+            if contents_unchanged {
+                for (name, _fs_type, _inode) in &cheap_entries {
+                    if let Some(dirent) = state.children.get(name) {
+                        if !dirent.explicit_mapping {
+                            reply.add(dirent.node.inode(), pos, dirent.node.file_type_cached(), name);
+                            pos += 1;
+                        }
+                    }
+                }
+                // The mtime moved but the contents didn't (e.g. a no-op rewrite): remember the new
+                // mtime so that the next call can again take the cheap mtime-only fast path above.
+                // A second-ambiguous mtime is not recorded, as it can't vouch for later calls.
+                if !ambiguous {
+                    state.scan = Some(DirScan { mtime: dir_mtime, digest });
+                }
+                return Ok(());
+            }
+        }
+
+        // The directory has actually changed: fan the metadata() calls out across a bounded
+        // worker pool.  Neither this nor the collection above touches state, so none of it needs
+        // the lock held.
+        let stated_entries = stat_entries(entries);
 
-        let entries = fs::read_dir(state.underlying_path.as_ref().unwrap())?;
-        for entry in entries {
-            let entry = entry?;
+        // Re-acquire the lock only to fold the collected results back in, deterministically and in
+        // the original directory order.
+        let mut state = self.state.lock().unwrap();
+        let mut seen = HashSet::with_capacity(stated_entries.len());
+        for (entry, metadata) in stated_entries {
             let name = entry.file_name();
+            seen.insert(name.clone());
 
             if let Some(dirent) = state.children.get(&name) {
                 if dirent.explicit_mapping {
@@ -284,8 +537,8 @@ impl Node for Dir {
                 }
             }
 
-            let path = state.underlying_path.as_ref().unwrap().join(&name);
-            let fs_attr = entry.metadata()?;
+            let fs_attr = metadata?;
+            let path = underlying_path.join(&name);
             let fs_type = conv::filetype_fs_to_fuse(&path, fs_attr.file_type());
             let child = cache.get_or_create(ids, &path, &fs_attr, self.writable);
 
@@ -296,15 +549,105 @@ impl Node for Dir {
                 node: child.clone(),
                 explicit_mapping: false,
             };
-            // TODO(jmmv): We should remove stale entries at some point (possibly here), but the Go
-            // variant does not do this so any implications of this are not tested.  The reason this
-            // hasn't caused trouble yet is because: on readdir, we don't use any contents from
-            // state.children that correspond to unmapped entries, and any stale entries visited
-            // during lookup will result in an ENOENT.
             state.children.insert(name, dirent);
 
             pos += 1;
         }
+
+        // Drop stale non-explicit entries that no longer exist on disk, so that a subsequent
+        // `lookup` of a deleted file reports ENOENT instead of resurrecting a stale node.
+        evict_stale_children(&mut state.children, &seen);
+
+        // A scan whose directory mtime was second-ambiguous is not recorded: the directory could
+        // change again within that same second without its mtime moving, so a later call must
+        // not take the mtime-only fast path on the strength of it.
+        state.scan = if ambiguous { None } else { Some(DirScan { mtime: dir_mtime, digest }) };
+
         Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_second_ambiguous_same_second_is_ambiguous() {
+        let now = time::Timespec::new(1_000, 500_000_000);
+        let mtime = time::Timespec::new(1_000, 0);
+        assert!(is_second_ambiguous(mtime, now));
+    }
+
+    #[test]
+    fn is_second_ambiguous_past_second_is_not_ambiguous() {
+        let now = time::Timespec::new(1_000, 0);
+        let mtime = time::Timespec::new(999, 999_999_999);
+        assert!(!is_second_ambiguous(mtime, now));
+    }
+
+    #[test]
+    fn mtime_fast_path_no_prior_scan_does_not_apply() {
+        let mtime = time::Timespec::new(1_000, 0);
+        assert!(!mtime_fast_path_applies(None, mtime, false));
+    }
+
+    #[test]
+    fn mtime_fast_path_ambiguous_mtime_forces_full_scan() {
+        let mtime = time::Timespec::new(1_000, 0);
+        let scan = DirScan { mtime, digest: [0; 20] };
+        assert!(!mtime_fast_path_applies(Some(&scan), mtime, true));
+    }
+
+    #[test]
+    fn mtime_fast_path_matching_unambiguous_mtime_applies() {
+        let mtime = time::Timespec::new(1_000, 0);
+        let scan = DirScan { mtime, digest: [0; 20] };
+        assert!(mtime_fast_path_applies(Some(&scan), mtime, false));
+    }
+
+    #[test]
+    fn mtime_fast_path_mismatched_mtime_does_not_apply() {
+        let scan = DirScan { mtime: time::Timespec::new(1_000, 0), digest: [0; 20] };
+        let new_mtime = time::Timespec::new(1_001, 0);
+        assert!(!mtime_fast_path_applies(Some(&scan), new_mtime, false));
+    }
+
+    #[test]
+    fn digest_fast_path_no_prior_scan_does_not_apply() {
+        assert!(!digest_fast_path_applies(None, &[0; 20]));
+    }
+
+    #[test]
+    fn digest_fast_path_matching_digest_applies() {
+        let scan = DirScan { mtime: time::Timespec::new(1_000, 0), digest: [7; 20] };
+        assert!(digest_fast_path_applies(Some(&scan), &[7; 20]));
+    }
+
+    #[test]
+    fn digest_fast_path_mismatched_digest_forces_full_scan() {
+        let scan = DirScan { mtime: time::Timespec::new(1_000, 0), digest: [7; 20] };
+        assert!(!digest_fast_path_applies(Some(&scan), &[8; 20]));
+    }
+
+    #[test]
+    fn evict_stale_children_drops_missing_non_explicit_entries() {
+        let now = time::Timespec::new(1_000, 0);
+        let mut children = HashMap::new();
+        children.insert(OsString::from("gone"),
+            Dirent { node: Dir::new_empty(1, None, now), explicit_mapping: false });
+        children.insert(OsString::from("kept"),
+            Dirent { node: Dir::new_empty(2, None, now), explicit_mapping: false });
+        children.insert(OsString::from("mapped"),
+            Dirent { node: Dir::new_empty(3, None, now), explicit_mapping: true });
+
+        let mut seen = HashSet::new();
+        seen.insert(OsString::from("kept"));
+
+        evict_stale_children(&mut children, &seen);
+
+        assert_eq!(2, children.len());
+        assert!(children.contains_key(&OsString::from("kept")));
+        assert!(children.contains_key(&OsString::from("mapped")));
+        assert!(!children.contains_key(&OsString::from("gone")));
+    }
 }
\ No newline at end of file
@@ -13,14 +13,61 @@
 // under the License.
 
 extern crate fuse;
+extern crate libc;
+extern crate memmap;
+extern crate time;
 
 use nix::errno;
+use nix::sys::statfs;
+use nix::unistd;
 use nodes::{ArcHandle, ArcNode, AttrDelta, Handle, KernelError, Node, NodeResult, conv, setattr};
+use nodes::dir::is_second_ambiguous;
+use self::memmap::Mmap;
+use std::cmp;
 use std::fs;
 use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
+/// Minimum file size, in bytes, above which `File::open` attempts to memory-map the underlying
+/// file instead of serving reads via `read_at`.  Below this size the extra mmap/munmap overhead
+/// isn't worth it.
+const MMAP_MIN_SIZE: u64 = 128 * 1024;
+
+/// Local filesystem types that are safe to memory-map.
+///
+/// This is deliberately an allow-list, not a deny-list of known-remote types: a SIGBUS from a
+/// concurrent truncation is tolerable, but a SIGBUS because some *other* process on a remote
+/// server went away or the network blipped is not recoverable and takes the whole FUSE daemon
+/// down with it.  Anything not on this list -- NFS, CIFS/SMB, FUSE-backed mounts such as sshfs, or
+/// any filesystem type we don't recognize -- is treated as unsafe.
+const SAFE_TO_MMAP_FS_TYPES: &[statfs::FsType] = &[
+    statfs::EXT2_SUPER_MAGIC,
+    statfs::XFS_SUPER_MAGIC,
+    statfs::BTRFS_SUPER_MAGIC,
+    statfs::TMPFS_MAGIC,
+    statfs::ISOFS_SUPER_MAGIC,
+    statfs::UDF_SUPER_MAGIC,
+    statfs::MSDOS_SUPER_MAGIC,
+    statfs::NTFS_SB_MAGIC,
+    statfs::JFS_SUPER_MAGIC,
+    statfs::REISERFS_SUPER_MAGIC,
+];
+
+/// Returns true if `file` lives on a filesystem we can't safely memory-map.
+///
+/// See `SAFE_TO_MMAP_FS_TYPES` for the rationale.  When the probe itself fails, assume the worst
+/// and decline to map.
+fn is_unsafe_to_mmap(file: &fs::File) -> bool {
+    match statfs::fstatfs(file) {
+        Ok(stat) => !SAFE_TO_MMAP_FS_TYPES.contains(&stat.filesystem_type()),
+        Err(e) => {
+            warn!("fstatfs failed; not memory-mapping this file to be safe: {}", e);
+            true
+        },
+    }
+}
+
 impl Handle for fs::File {
     fn read(&self, offset: i64, size: u32) -> NodeResult<Vec<u8>> {
         let mut buffer = vec![0; size as usize];
@@ -45,6 +92,177 @@ impl Handle for fs::File {
     }
 }
 
+/// A read/write handle to an open file, optionally backed by a memory-mapped view of its
+/// contents so that large sequential reads become slice copies instead of `read_at` syscalls.
+///
+/// `mmap` is only ever populated for handles opened read-only on a local filesystem; see
+/// `File::open` for the decision of when to map.
+struct FileHandle {
+    file: fs::File,
+    mmap: Option<Mmap>,
+}
+
+impl Handle for FileHandle {
+    fn read(&self, offset: i64, size: u32) -> NodeResult<Vec<u8>> {
+        match &self.mmap {
+            Some(mmap) => {
+                let start = cmp::min(offset as usize, mmap.len());
+                let end = cmp::min(start + size as usize, mmap.len());
+                Ok(mmap[start..end].to_vec())
+            },
+            None => self.file.read(offset, size),
+        }
+    }
+
+    fn write(&self, offset: i64, data: &[u8]) -> NodeResult<u32> {
+        self.file.write(offset, data)
+    }
+}
+
+/// A pluggable source of content and metadata for a `File` node that does not necessarily
+/// correspond to a path on the underlying file system.
+///
+/// This is what lets a sandbox serve content that has no on-disk origin -- e.g. a small generated
+/// config or a redirect stub splatted from an in-memory buffer -- using exactly the same node
+/// machinery (caching, FUSE wiring) as on-disk files.
+pub trait VirtualFile: Send + Sync {
+    /// Returns the file's current attributes, given the node's assigned `inode`.
+    fn getattr(&self, inode: u64) -> NodeResult<fuse::FileAttr>;
+
+    /// Applies the requested attribute changes and returns the resulting attributes.
+    fn setattr(&self, inode: u64, delta: &AttrDelta) -> NodeResult<fuse::FileAttr>;
+
+    /// Opens a handle to read and/or write the file's contents.
+    fn open(&self, flags: u32) -> NodeResult<ArcHandle>;
+}
+
+/// Where a file's content and metadata are sourced from.
+#[derive(Clone)]
+enum Backend {
+    /// Backed by a path on the underlying file system.  Becomes `None` once `delete()` has been
+    /// called, at which point the node can no longer be reopened.
+    Path(Option<PathBuf>),
+    /// Backed by a pluggable, possibly in-memory, content provider.  Becomes `None` once
+    /// `delete()` has been called, at which point the node can no longer be reopened.
+    Virtual(Option<Arc<VirtualFile>>),
+}
+
+/// Mutable data backing a `BufferFile`.
+struct BufferFileState {
+    content: Vec<u8>,
+    atime: time::Timespec,
+    mtime: time::Timespec,
+    ctime: time::Timespec,
+    crtime: time::Timespec,
+    perm: u16,
+    uid: u32,
+    gid: u32,
+}
+
+impl BufferFileState {
+    fn to_attr(&self, inode: u64) -> fuse::FileAttr {
+        let size = self.content.len() as u64;
+        fuse::FileAttr {
+            ino: inode,
+            kind: fuse::FileType::RegularFile,
+            nlink: 1,
+            size,
+            blocks: (size + 511) / 512,
+            atime: self.atime,
+            mtime: self.mtime,
+            ctime: self.ctime,
+            crtime: self.crtime,
+            perm: self.perm,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+}
+
+/// A `VirtualFile` backed by an in-memory byte buffer.
+///
+/// This is what lets a mapping splat synthetic content -- e.g. a small generated config or a
+/// redirect stub -- into a sandbox without ever materializing it on disk, which is handy for tests
+/// and for layering tooling-generated files over a real tree.
+pub struct BufferFile {
+    // Wrapped in its own `Arc` (rather than just a bare `Mutex`) so that `open()` -- which only
+    // gets `&self`, not `Arc<Self>` -- can hand a `BufferHandle` a clone of the same underlying
+    // state that `getattr`/`setattr` operate on.
+    state: Arc<Mutex<BufferFileState>>,
+}
+
+impl BufferFile {
+    /// Creates a new buffer-backed file with the given initial `content`.
+    ///
+    /// `writable` controls the initial permission bits only (0644 vs. 0444); it does not affect
+    /// whether the content can be mutated through `setattr`/`Handle::write`, which is instead
+    /// gated by the `File` node's own `writable` flag as for any other backend.
+    pub fn new(content: Vec<u8>, writable: bool, now: time::Timespec) -> BufferFile {
+        let state = BufferFileState {
+            content,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            perm: if writable { 0o644 } else { 0o444 },
+            uid: unistd::getuid().as_raw(),
+            gid: unistd::getgid().as_raw(),
+        };
+        BufferFile { state: Arc::new(Mutex::from(state)) }
+    }
+}
+
+impl VirtualFile for BufferFile {
+    fn getattr(&self, inode: u64) -> NodeResult<fuse::FileAttr> {
+        let state = self.state.lock().unwrap();
+        Ok(state.to_attr(inode))
+    }
+
+    /// Applies only a size change, truncating or zero-extending the content.  Requested changes to
+    /// permissions, ownership or timestamps are ignored; mtime is always set to the current time.
+    fn setattr(&self, inode: u64, delta: &AttrDelta) -> NodeResult<fuse::FileAttr> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(size) = delta.size {
+            state.content.resize(size as usize, 0);
+        }
+        state.mtime = time::get_time();
+        Ok(state.to_attr(inode))
+    }
+
+    fn open(&self, _flags: u32) -> NodeResult<ArcHandle> {
+        Ok(Arc::new(BufferHandle { state: self.state.clone() }))
+    }
+}
+
+/// A handle onto an open `BufferFile`, sharing its content with every other open handle and with
+/// `getattr`/`setattr` on the owning node.
+struct BufferHandle {
+    state: Arc<Mutex<BufferFileState>>,
+}
+
+impl Handle for BufferHandle {
+    fn read(&self, offset: i64, size: u32) -> NodeResult<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let start = cmp::min(offset as usize, state.content.len());
+        let end = cmp::min(start + size as usize, state.content.len());
+        Ok(state.content[start..end].to_vec())
+    }
+
+    fn write(&self, offset: i64, data: &[u8]) -> NodeResult<u32> {
+        let mut state = self.state.lock().unwrap();
+        let offset = offset as usize;
+        let end = offset + data.len();
+        if state.content.len() < end {
+            state.content.resize(end, 0);
+        }
+        state.content[offset..end].copy_from_slice(data);
+        state.mtime = time::get_time();
+        Ok(data.len() as u32)
+    }
+}
+
 /// Representation of a file node.
 ///
 /// File nodes represent all kinds of files (except for directories and symlinks), not just regular
@@ -52,13 +270,24 @@ impl Handle for fs::File {
 pub struct File {
     inode: u64,
     writable: bool,
+    /// How long a cached `attr` may be served without re-stating the underlying file.  A `None`
+    /// value disables attribute caching entirely, which callers running reproducible builds can
+    /// use to guarantee every `getattr` reflects the current on-disk state.  Unused for virtual
+    /// backends, which are responsible for their own freshness.
+    attr_ttl: Option<time::Duration>,
     state: Mutex<MutableFile>,
 }
 
 /// Holds the mutable data of a file node.
 struct MutableFile {
-    underlying_path: Option<PathBuf>,
+    backend: Backend,
     attr: fuse::FileAttr,
+    /// Wall-clock time at which `attr` was last refreshed from disk, and whether that refresh
+    /// landed in the same wall-clock second as the observed mtime.  Such "second-ambiguous"
+    /// entries can't be trusted: a 1-second-granularity mtime could change again within that same
+    /// second without us ever seeing a different value, so they must always be re-stated.  `None`
+    /// until the first stat, and always `None` for virtual backends.
+    cached_at: Option<(time::Timespec, bool)>,
 }
 
 impl File {
@@ -76,35 +305,81 @@ impl File {
     /// `fs_attr` is an input parameter because, by the time we decide to instantiate a file
     /// node (e.g. as we discover directory entries during readdir or lookup), we have already
     /// issued a stat on the underlying file system and we cannot re-do it for efficiency reasons.
-    pub fn new_mapped(inode: u64, underlying_path: &Path, fs_attr: &fs::Metadata, writable: bool)
-        -> ArcNode {
+    ///
+    /// `attr_ttl` is the mount-time knob controlling how long `getattr` may serve a cached
+    /// attribute before re-stating the underlying file; `None` disables caching.
+    pub fn new_mapped(inode: u64, underlying_path: &Path, fs_attr: &fs::Metadata, writable: bool,
+        attr_ttl: Option<time::Duration>) -> ArcNode {
         if !File::supports_type(fs_attr.file_type()) {
             panic!("Can only construct based on non-directories / non-symlinks");
         }
         let attr = conv::attr_fs_to_fuse(underlying_path, inode, &fs_attr);
 
         let state = MutableFile {
-            underlying_path: Some(PathBuf::from(underlying_path)),
+            backend: Backend::Path(Some(PathBuf::from(underlying_path))),
             attr: attr,
+            cached_at: None,
+        };
+
+        Arc::new(File { inode, writable, attr_ttl, state: Mutex::from(state) })
+    }
+
+    /// Creates a new file backed by a pluggable `VirtualFile` instead of a path on the underlying
+    /// file system.
+    ///
+    /// `inode` is the node number to assign to the created file.  `attr` holds the attributes to
+    /// expose until the first `getattr` call refreshes them from `backend`.
+    pub fn new_virtual(inode: u64, backend: Arc<VirtualFile>, attr: fuse::FileAttr, writable: bool)
+        -> ArcNode {
+        let state = MutableFile {
+            backend: Backend::Virtual(Some(backend)),
+            attr,
+            cached_at: None,
         };
 
-        Arc::new(File { inode, writable, state: Mutex::from(state) })
+        Arc::new(File { inode, writable, attr_ttl: None, state: Mutex::from(state) })
     }
 
     /// Same as `getattr` but with the node already locked.
-    fn getattr_locked(inode: u64, state: &mut MutableFile) -> NodeResult<fuse::FileAttr> {
-        if let Some(path) = &state.underlying_path {
-            let fs_attr = fs::symlink_metadata(path)?;
-            if !File::supports_type(fs_attr.file_type()) {
-                warn!("Path {} backing a file node is no longer a file; got {:?}",
-                    path.display(), fs_attr.file_type());
-                return Err(KernelError::from_errno(errno::Errno::EIO));
+    fn getattr_locked(inode: u64, attr_ttl: Option<time::Duration>, state: &mut MutableFile)
+        -> NodeResult<fuse::FileAttr> {
+        if let Some(ttl) = attr_ttl {
+            if let Some((cached_at, ambiguous)) = state.cached_at {
+                if !ambiguous && time::get_time() - cached_at < ttl {
+                    return Ok(state.attr);
+                }
             }
-            state.attr = conv::attr_fs_to_fuse(path, inode, &fs_attr);
+        }
+
+        match state.backend.clone() {
+            Backend::Path(None) => (),
+            Backend::Path(Some(path)) => {
+                let fs_attr = fs::symlink_metadata(&path)?;
+                if !File::supports_type(fs_attr.file_type()) {
+                    warn!("Path {} backing a file node is no longer a file; got {:?}",
+                        path.display(), fs_attr.file_type());
+                    return Err(KernelError::from_errno(errno::Errno::EIO));
+                }
+                let now = time::get_time();
+                state.attr = conv::attr_fs_to_fuse(&path, inode, &fs_attr);
+                state.cached_at = Some((now, is_second_ambiguous(state.attr.mtime, now)));
+            },
+            Backend::Virtual(None) => (),
+            Backend::Virtual(Some(backend)) => {
+                state.attr = backend.getattr(inode)?;
+            },
         }
 
         Ok(state.attr)
     }
+
+    /// Invalidates any cached attribute, forcing the next `getattr` to re-stat the underlying file.
+    ///
+    /// This must be called whenever we mutate the file out from under a cached attribute, since
+    /// otherwise a subsequent `getattr` could keep serving stale data until the TTL expires.
+    fn clear_cached_attr(state: &mut MutableFile) {
+        state.cached_at = None;
+    }
 }
 
 impl Node for File {
@@ -123,30 +398,185 @@ impl Node for File {
 
     fn delete(&self) {
         let mut state = self.state.lock().unwrap();
-        assert!(
-            state.underlying_path.is_some(),
-            "Delete already called or trying to delete an explicit mapping");
-        state.underlying_path = None;
+        match &mut state.backend {
+            Backend::Path(path @ Some(_)) => *path = None,
+            Backend::Path(None) =>
+                panic!("Delete already called or trying to delete an explicit mapping"),
+            Backend::Virtual(backend @ Some(_)) => *backend = None,
+            Backend::Virtual(None) =>
+                panic!("Delete already called or trying to delete an explicit mapping"),
+        }
+        File::clear_cached_attr(&mut state);
     }
 
     fn getattr(&self) -> NodeResult<fuse::FileAttr> {
         let mut state = self.state.lock().unwrap();
-        File::getattr_locked(self.inode, &mut state)
+        File::getattr_locked(self.inode, self.attr_ttl, &mut state)
     }
 
     fn open(&self, flags: u32) -> NodeResult<ArcHandle> {
         let state = self.state.lock().unwrap();
 
+        let path = match &state.backend {
+            Backend::Path(Some(path)) => path.clone(),
+            Backend::Path(None) =>
+                panic!("Don't know how to handle a request to reopen a deleted file"),
+            Backend::Virtual(Some(backend)) => {
+                // Apply the same writable check as for on-disk files before handing out a handle.
+                conv::flags_to_openoptions(flags, self.writable)?;
+                return backend.open(flags);
+            },
+            Backend::Virtual(None) =>
+                panic!("Don't know how to handle a request to reopen a deleted file"),
+        };
+
         let options = conv::flags_to_openoptions(flags, self.writable)?;
-        let path = state.underlying_path.as_ref().expect(
-            "Don't know how to handle a request to reopen a deleted file");
-        let file = options.open(path)?;
-        Ok(Arc::from(file))
+        let file = options.open(&path)?;
+
+        // Only ever mmap handles opened read-only for *this* call: a writable mapping would need
+        // to stay coherent with writes going through write_at, which isn't worth the complexity
+        // here.  This must key off this open()'s own flags, not the node's mount-wide `writable`
+        // flag -- a writable node can still be opened O_RDONLY by a caller, and that handle is
+        // just as safe to mmap as one on a read-only node.
+        let read_only = (flags as i32 & libc::O_ACCMODE) == libc::O_RDONLY;
+        let mmap = if read_only {
+            match file.metadata() {
+                Ok(metadata) if metadata.len() >= MMAP_MIN_SIZE && !is_unsafe_to_mmap(&file) => {
+                    match unsafe { Mmap::map(&file) } {
+                        Ok(mmap) => Some(mmap),
+                        Err(e) => {
+                            warn!("Failed to mmap {}; falling back to read_at: {}", path.display(), e);
+                            None
+                        },
+                    }
+                },
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        Ok(Arc::new(FileHandle { file, mmap }))
     }
 
     fn setattr(&self, delta: &AttrDelta) -> NodeResult<fuse::FileAttr> {
         let mut state = self.state.lock().unwrap();
-        state.attr = setattr(state.underlying_path.as_ref(), &state.attr, delta)?;
+        state.attr = match &state.backend {
+            Backend::Path(path) => setattr(path.as_ref(), &state.attr, delta)?,
+            Backend::Virtual(Some(backend)) => backend.setattr(self.inode, delta)?,
+            // Mirrors `Backend::Path(None)` being accepted by the free `setattr` helper above: a
+            // setattr racing a delete on a still-open handle is valid and must not panic or fail,
+            // it just has nothing left to apply the change to.
+            Backend::Virtual(None) => state.attr,
+        };
+        File::clear_cached_attr(&mut state);
         Ok(state.attr)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::thread;
+    use std::time::Duration as StdDuration;
+
+    /// Returns a path under the system temporary directory that no other test or run is using.
+    fn unique_temp_path() -> PathBuf {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let mut path = std::env::temp_dir();
+        path.push(format!("sandboxfs-file-test-{}-{}", std::process::id(), id));
+        path
+    }
+
+    /// A real on-disk file that removes itself when dropped, so that TTL-caching tests can stat a
+    /// genuine path without leaking files into the system temporary directory.
+    struct TempFile {
+        path: PathBuf,
+    }
+
+    impl TempFile {
+        fn with_contents(contents: &[u8]) -> TempFile {
+            let path = unique_temp_path();
+            fs::write(&path, contents).unwrap();
+            TempFile { path }
+        }
+
+        fn node(&self, attr_ttl: Option<time::Duration>) -> ArcNode {
+            let fs_attr = fs::symlink_metadata(&self.path).unwrap();
+            File::new_mapped(1, &self.path, &fs_attr, true, attr_ttl)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn getattr_within_ttl_serves_cached_attr_despite_on_disk_change() {
+        let file = TempFile::with_contents(b"before");
+        let node = file.node(Some(time::Duration::seconds(5)));
+
+        // Cross into a later wall-clock second than the file's own mtime so that the very first
+        // `getattr` isn't itself second-ambiguous, which would otherwise force every subsequent
+        // call to re-stat regardless of the TTL.
+        thread::sleep(StdDuration::from_millis(1100));
+        let initial = node.getattr().unwrap();
+        assert_eq!(6, initial.size);
+
+        fs::write(&file.path, b"after-a-longer-write").unwrap();
+        let cached = node.getattr().unwrap();
+        assert_eq!(initial.size, cached.size, "a getattr within the TTL must not re-stat");
+    }
+
+    #[test]
+    fn getattr_after_ttl_expiry_restats_from_disk() {
+        let file = TempFile::with_contents(b"before");
+        let node = file.node(Some(time::Duration::milliseconds(200)));
+
+        thread::sleep(StdDuration::from_millis(1100));
+        let initial = node.getattr().unwrap();
+        assert_eq!(6, initial.size);
+
+        fs::write(&file.path, b"after-a-longer-write").unwrap();
+        thread::sleep(StdDuration::from_millis(300));
+        let refreshed = node.getattr().unwrap();
+        assert_eq!(20, refreshed.size, "a getattr past the TTL must re-stat");
+    }
+
+    #[test]
+    fn delete_on_virtual_backed_node_does_not_panic() {
+        let now = time::get_time();
+        let buffer = BufferFile::new(b"content".to_vec(), true, now);
+        let attr = buffer.getattr(1).unwrap();
+        let node = File::new_virtual(1, Arc::new(buffer), attr, true);
+
+        // This is the exact scenario the review flagged: `new_virtual` happily accepts
+        // `writable: true`, and a `rm` of such a mapped file is a normal FUSE unlink -- it must not
+        // crash the daemon the way an unconditional panic would.
+        node.delete();
+    }
+
+    #[test]
+    fn buffer_file_open_read_write_getattr_round_trip() {
+        let now = time::get_time();
+        let buffer = BufferFile::new(b"hello".to_vec(), true, now);
+        let attr = buffer.getattr(1).unwrap();
+        assert_eq!(5, attr.size);
+
+        let node = File::new_virtual(1, Arc::new(buffer), attr, true);
+        assert_eq!(5, node.getattr().unwrap().size);
+
+        let handle = node.open(libc::O_RDWR as u32).unwrap();
+        assert_eq!(b"hello".to_vec(), handle.read(0, 5).unwrap());
+
+        handle.write(5, b" world").unwrap();
+        assert_eq!(b" world".to_vec(), handle.read(5, 6).unwrap());
+        assert_eq!(b"hello world".to_vec(), handle.read(0, 11).unwrap());
+
+        assert_eq!(11, node.getattr().unwrap().size);
+    }
+}